@@ -1,6 +1,6 @@
 extern crate core;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use fast_image_resize as fr;
 use flate2::read::GzDecoder;
 use image::imageops::{overlay};
@@ -10,14 +10,22 @@ use itertools::{Itertools};
 use rusttype::{Font, Scale};
 use serde::{Deserialize, Serialize};
 use simple_logger::SimpleLogger;
+use std::collections::hash_map::DefaultHasher;
 use std::fs;
 use std::fs::File;
-use std::io::{BufReader};
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, Write};
 use std::num::NonZeroU32;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
 use structopt::StructOpt;
+use rayon::prelude::*;
 use num_format::{Locale, ToFormattedString};
 
+// How often (in frames) to persist a resume snapshot.
+const CHECKPOINT_INTERVAL: usize = 50;
+
 // {"segment":233023,"num":33}
 #[derive(Deserialize, Debug)]
 struct Segment {
@@ -68,12 +76,28 @@ impl From<&FileState> for Rgb<u8> {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl FileState {
+    // Index into `State::counts`, in declaration order.
+    fn index(&self) -> usize {
+        match self {
+            FileState::Present => 0,
+            FileState::DeleteMarker => 1,
+            FileState::Expired => 2,
+            FileState::DeleteMarkerDeleted => 3,
+            FileState::WeirdCase => 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct State {
     offsets: Vec<usize>,
     files: Vec<FileState>,
     image_size: usize,
     output_image_size: u32,
+    // Running totals per `FileState`, kept in sync by `set_item` so callers
+    // don't have to re-scan `files` every frame.
+    counts: [usize; 5],
 }
 
 impl State {
@@ -81,15 +105,28 @@ impl State {
         let files = vec![FileState::Present; total_size];
         let length = files.len();
         let image_size = ((length as f64).sqrt() as usize) + 1;
+        let mut counts = [0usize; 5];
+        counts[FileState::Present.index()] = length;
         State {
             offsets,
             files,
             image_size,
             output_image_size,
+            counts,
         }
     }
 
-    fn set_item(&mut self, segment: usize, number: usize, operation: &Operation) {
+    // The five state totals, in `FileState` declaration order:
+    // present, delete_marker, expired, delete_marker_deleted, weird_case.
+    fn counts(&self) -> [usize; 5] {
+        self.counts
+    }
+
+    // Applies an operation to a file, described by a segment and a number. Returns the
+    // file's prior state if this transition is the one that just made it a `WeirdCase`
+    // (as opposed to an event replaying against an already-weird file), so callers can
+    // report the offending transition without re-deriving it.
+    fn set_item(&mut self, segment: usize, number: usize, operation: &Operation) -> Option<FileState> {
         // Set a given file to a state, described by a segment and a number.
         // Segments and numbers are 1-indexed, so we need to subtract 1 from each of them
         // Calculate the offset
@@ -108,6 +145,8 @@ impl State {
                 )
             }
             Some(item) => {
+                let previous_index = item.index();
+                let previous_state = item.clone();
                 match (operation, &item) {
                     // Standard flow
                     (Operation::Delete, FileState::Present) => *item = FileState::DeleteMarker,
@@ -132,48 +171,111 @@ impl State {
                     (_, FileState::WeirdCase) => {}
                     _ => panic!("Failure: op={:?} item={:?}", operation, item),
                 }
+                let new_index = item.index();
+                if new_index != previous_index {
+                    self.counts[previous_index] -= 1;
+                    self.counts[new_index] += 1;
+                }
+                if *item == FileState::WeirdCase && previous_state != FileState::WeirdCase {
+                    Some(previous_state)
+                } else {
+                    None
+                }
             }
         }
     }
+}
 
-    fn get_frame(&self) -> RgbImage {
-        log::info!("Creating image...");
-        // The slowest part of the whole shebang.
-        let img =
-            image::ImageBuffer::from_fn(self.image_size as u32, self.image_size as u32, |x, y| {
-                let row_idx = y * self.image_size as u32;
-                let idx = row_idx + x;
-                match self.files.get(idx as usize) {
+// Builds the (unresized) state grid and Lanczos-resizes it down to `output_image_size`.
+// Takes a bare file slice rather than `&State` so a worker thread can render a
+// snapshot of `files` while the main thread keeps advancing the state machine.
+fn render_frame(files: &[FileState], image_size: usize, output_image_size: u32) -> RgbImage {
+    log::info!("Creating image...");
+    let width = image_size as u32;
+    let mut raw = vec![0u8; width as usize * image_size * 3];
+    // Each row `y` maps to a contiguous, non-overlapping slice of `raw`, so chunks can
+    // be filled in parallel with no write conflicts. This used to be a single-threaded
+    // `ImageBuffer::from_fn` call and was the slowest part of the whole shebang.
+    raw.par_chunks_mut(width as usize * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for x in 0..width as usize {
+                let idx = y * width as usize + x;
+                let color: Rgb<u8> = match files.get(idx) {
                     // I don't know how to make an Option<FileState> turn into an RGB value. Oh well.
                     None => Rgb([0, 0, 0]),
                     Some(v) => v.into(),
-                }
-            });
-        log::info!("Resizing image...");
-        // Taken from the fast-resize crate examples
-        let width = NonZeroU32::new(img.width()).unwrap();
-        let height = NonZeroU32::new(img.height()).unwrap();
-        let src_image =
-            fr::Image::from_vec_u8(width, height, img.into_raw(), fr::PixelType::U8x3).unwrap();
-
-        // Create container for data of destination image
-        let dst_width = NonZeroU32::new(self.output_image_size).unwrap();
-        let dst_height = NonZeroU32::new(self.output_image_size).unwrap();
-        let mut dst_image = fr::Image::new(dst_width, dst_height, src_image.pixel_type());
-
-        // Get mutable view of destination image data
-        let mut dst_view = dst_image.view_mut();
-        // Create Resizer instance and resize source image
-        // into buffer of destination image
-        let mut resizer = fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3));
-        resizer.resize(&src_image.view(), &mut dst_view).unwrap();
-        log::info!("Resized...");
-        RgbImage::from_raw(self.output_image_size, self.output_image_size, dst_image.buffer().to_vec()).expect("Error converting resized")
+                };
+                row[x * 3..x * 3 + 3].copy_from_slice(&color.0);
+            }
+        });
+    let img = RgbImage::from_raw(width, width, raw).expect("Error building frame buffer");
+
+    log::info!("Resizing image...");
+    // Taken from the fast-resize crate examples
+    let src_width = NonZeroU32::new(img.width()).unwrap();
+    let src_height = NonZeroU32::new(img.height()).unwrap();
+    let src_image =
+        fr::Image::from_vec_u8(src_width, src_height, img.into_raw(), fr::PixelType::U8x3).unwrap();
+
+    // Create container for data of destination image
+    let dst_width = NonZeroU32::new(output_image_size).unwrap();
+    let dst_height = NonZeroU32::new(output_image_size).unwrap();
+    let mut dst_image = fr::Image::new(dst_width, dst_height, src_image.pixel_type());
+
+    // Get mutable view of destination image data
+    let mut dst_view = dst_image.view_mut();
+    // Create Resizer instance and resize source image
+    // into buffer of destination image
+    let mut resizer = fr::Resizer::new(fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3));
+    resizer.resize(&src_image.view(), &mut dst_view).unwrap();
+    log::info!("Resized...");
+    RgbImage::from_raw(output_image_size, output_image_size, dst_image.buffer().to_vec())
+        .expect("Error converting resized")
+}
+
+// Everything needed to resume a run from where a previous one left off.
+#[derive(Serialize, Deserialize, Debug)]
+struct Checkpoint {
+    state: State,
+    bucket: DateTime<Utc>,
+    frame_idx: usize,
+    // The `--interval` window anchor in effect when this checkpoint was written, so a
+    // resumed `--interval` run folds buckets into the same windows as the original run
+    // instead of re-anchoring to wherever the post-resume event stream happens to start.
+    window_anchor: Option<DateTime<Utc>>,
+}
+
+impl Checkpoint {
+    fn path(state_dir: &Path) -> PathBuf {
+        state_dir.join("state.bin")
+    }
+
+    fn load(state_dir: &Path) -> Option<Self> {
+        let path = Self::path(state_dir);
+        if !path.exists() {
+            return None;
+        }
+        let file = File::open(&path).expect("Error reading checkpoint");
+        Some(bincode::deserialize_from(BufReader::new(file)).expect("Error decoding checkpoint"))
+    }
+
+    fn save(&self, state_dir: &Path) {
+        let bytes = bincode::serialize(self).expect("Error encoding checkpoint");
+        fs::write(Self::path(state_dir), bytes).expect("Error writing checkpoint");
     }
 }
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "example", about = "An example of StructOpt usage.")]
+#[structopt(name = "s3-deletion-visualizer", about = "Render an S3 deletion log as a frame sequence, or verify it without rendering.")]
+enum Cli {
+    /// Render the deletion log as a sequence of frames (PNGs, GIF, or MP4).
+    Render(Opt),
+    /// Run the same ingestion and state machine, but emit a timeseries report instead of images.
+    Verify(VerifyOpt),
+}
+
+#[derive(Debug, Clone, StructOpt)]
 struct Opt {
     #[structopt(parse(from_os_str))]
     segments: PathBuf,
@@ -185,19 +287,323 @@ struct Opt {
     state_dir: PathBuf,
 
     output_size: u32,
+
+    /// Resume from the snapshot left behind in `state_dir` by a previous run, if any.
+    #[structopt(long)]
+    resume: bool,
+
+    /// Render one frame per fixed wall-clock window (e.g. "15m") instead of one frame
+    /// per distinct `bucket` timestamp, so playback speed doesn't depend on log granularity.
+    #[structopt(long)]
+    interval: Option<humantime::Duration>,
+
+    /// Discard events whose `bucket` is before this timestamp (same format as the
+    /// event log's `bucket` field, e.g. "2022-09-02 15:55:00.0").
+    #[structopt(long, parse(try_from_str = parse_datetime))]
+    from: Option<DateTime<Utc>>,
+
+    /// Discard events whose `bucket` is after this timestamp.
+    #[structopt(long, parse(try_from_str = parse_datetime))]
+    to: Option<DateTime<Utc>>,
+
+    /// Multiplies the effective time distance between windows when computing the
+    /// displayed "Hours" and "Per second" figures.
+    #[structopt(long, default_value = "1.0")]
+    scale: f64,
+
+    /// How frames are emitted: loose numbered PNGs (the default, for backward
+    /// compatibility), or a single animated GIF/MP4 streamed straight from memory.
+    #[structopt(long, default_value = "frames")]
+    output_format: OutputFormat,
+
+    /// Playback rate for `--output-format gif`/`mp4`. Ignored in `frames` mode.
+    #[structopt(long, default_value = "10")]
+    fps: u32,
 }
 
-// 940370485
-// 940360641
+#[derive(Debug, StructOpt)]
+struct VerifyOpt {
+    #[structopt(parse(from_os_str))]
+    segments: PathBuf,
 
-fn main() {
-    SimpleLogger::new().init().unwrap();
+    #[structopt(parse(from_os_str))]
+    events: PathBuf,
+
+    /// Where to write the CSV timeseries report.
+    #[structopt(parse(from_os_str))]
+    report: PathBuf,
+
+    /// Discard events whose `bucket` is before this timestamp.
+    #[structopt(long, parse(try_from_str = parse_datetime))]
+    from: Option<DateTime<Utc>>,
+
+    /// Discard events whose `bucket` is after this timestamp.
+    #[structopt(long, parse(try_from_str = parse_datetime))]
+    to: Option<DateTime<Utc>>,
+}
+
+fn parse_datetime(s: &str) -> Result<DateTime<Utc>, String> {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f")
+        .map(|naive| Utc.from_utc_datetime(&naive))
+        .map_err(|e| format!("invalid datetime {:?}: {}", s, e))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Frames,
+    Gif,
+    Mp4,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "frames" => Ok(OutputFormat::Frames),
+            "gif" => Ok(OutputFormat::Gif),
+            "mp4" => Ok(OutputFormat::Mp4),
+            other => Err(format!(
+                "unknown output format {:?}, expected one of: frames, gif, mp4",
+                other
+            )),
+        }
+    }
+}
+
+// Pipes raw rgb24 frames into ffmpeg over stdin to produce an MP4, since there's no
+// pure-Rust mp4 muxer in our dependency tree.
+struct Mp4Encoder {
+    child: std::process::Child,
+}
+
+impl Mp4Encoder {
+    fn new(path: &Path, width: u32, height: u32, fps: u32) -> Self {
+        let child = std::process::Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-f",
+                "rawvideo",
+                "-pix_fmt",
+                "rgb24",
+                "-s",
+                &format!("{}x{}", width, height),
+                "-r",
+                &fps.to_string(),
+                "-i",
+                "-",
+                "-pix_fmt",
+                "yuv420p",
+            ])
+            .arg(path)
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .expect("Error spawning ffmpeg - is it installed and on PATH?");
+        Mp4Encoder { child }
+    }
+
+    fn write_frame(&mut self, rgb: &[u8]) {
+        self.child
+            .stdin
+            .as_mut()
+            .expect("ffmpeg stdin already closed")
+            .write_all(rgb)
+            .expect("Error writing frame to ffmpeg");
+    }
+
+    fn finish(mut self) {
+        drop(self.child.stdin.take());
+        self.child.wait().expect("Error waiting for ffmpeg to finish encoding");
+    }
+}
+
+// Where rendered frames go: loose PNGs, or a single animated encoder fed one frame
+// at a time. Opened once before the bucket loop and finalized after it.
+enum FrameSink {
+    Frames,
+    Gif(gif::Encoder<File>, u32),
+    Mp4(Mp4Encoder),
+}
+
+impl FrameSink {
+    fn new(opt: &Opt, width: u32, height: u32) -> Self {
+        match opt.output_format {
+            OutputFormat::Frames => FrameSink::Frames,
+            OutputFormat::Gif => {
+                let path = opt.state_dir.join("output.gif");
+                let file = File::create(&path).expect("Error creating gif file");
+                let encoder = gif::Encoder::new(file, width as u16, height as u16, &[])
+                    .expect("Error creating gif encoder");
+                FrameSink::Gif(encoder, opt.fps)
+            }
+            OutputFormat::Mp4 => {
+                let path = opt.state_dir.join("output.mp4");
+                FrameSink::Mp4(Mp4Encoder::new(&path, width, height, opt.fps))
+            }
+        }
+    }
+
+    fn write(&mut self, idx: usize, state_dir: &Path, image: &RgbImage) {
+        match self {
+            FrameSink::Frames => {
+                let save_path = state_dir.join(format!("{:0width$}.png", idx, width = 4));
+                let new_bytes = image.as_raw();
+                let already_up_to_date = save_path.exists()
+                    && image::open(&save_path)
+                        .map(|existing| hash_bytes(existing.as_bytes()) == hash_bytes(new_bytes))
+                        .unwrap_or(false);
+                if already_up_to_date {
+                    log::info!("Frame {} unchanged, skipping write", idx);
+                } else {
+                    image.save(&save_path).expect("Error saving image");
+                }
+            }
+            FrameSink::Gif(encoder, fps) => {
+                let pixels = image.clone().into_raw();
+                let mut frame =
+                    gif::Frame::from_rgb(image.width() as u16, image.height() as u16, &pixels);
+                // `gif::Frame`'s delay is in hundredths of a second; left at its default of 0
+                // it plays back as fast as the viewer allows, silently ignoring `--fps`.
+                frame.delay = (100 / (*fps).max(1)) as u16;
+                encoder.write_frame(&frame).expect("Error writing gif frame");
+            }
+            FrameSink::Mp4(encoder) => encoder.write_frame(image.as_raw()),
+        }
+    }
+
+    fn finish(self) {
+        if let FrameSink::Mp4(encoder) = self {
+            encoder.finish();
+        }
+    }
+}
+
+// Computes a quick content hash so we can skip re-writing a frame that's identical
+// to what's already on disk.
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Everything a worker thread needs to render and save one frame, independent of the
+// live `State` - so the main loop can keep mutating `state.files` for bucket N+1
+// while this snapshot of bucket N is still being rendered/encoded.
+struct FrameJob {
+    idx: usize,
+    key: DateTime<Utc>,
+    duration_since_start: chrono::Duration,
+    total_actions: i64,
+    elapsed_secs: i64,
+    counts: [usize; 5],
+    files: Arc<Vec<FileState>>,
+}
+
+// Draws the text overlay and grid for one frame. `elapsed_secs` is the time distance
+// this frame represents - the window length in `--interval` mode, otherwise the gap
+// since the previous bucket - before `--scale` is applied.
+fn compose_overlay_image(font: &Font, opt: &Opt, image_size: usize, job: &FrameJob) -> RgbImage {
+    let effective_elapsed_secs = ((job.elapsed_secs as f64) * opt.scale).max(1.0);
+    let actions_per_second = (job.total_actions as f64 / effective_elapsed_secs) as i64;
+    let displayed_hours =
+        (job.duration_since_start.num_seconds() as f64 * opt.scale / 3600.0) as i64;
+
+    let [present, delete_marker, expired, delete_marker_deleted, weird_case] = job.counts;
+    log::info!("Present = {}, delete_marker = {}, expired = {}, delete_marked_deleted = {} weird_case = {}", present, delete_marker, expired, delete_marker_deleted, weird_case);
+    log::info!("Per second: {}", actions_per_second);
+
+    let mut overlay_image =
+        RgbImage::from_pixel(opt.output_size, opt.output_size + 400, Rgb([255, 255, 255]));
+
+    let scale = Scale {
+        x: 45.0,
+        y: 45.0,
+    };
+
+    let line_buffer = 20;
+    let mut start_y = 25;
+
+    let text_items = vec![
+        format!("Hours: {}", displayed_hours),
+        format!("Present: {}", present.to_formatted_string(&Locale::en)),
+        format!("Delete Marker: {}", delete_marker.to_formatted_string(&Locale::en)),
+        format!("Expired: {}", expired.to_formatted_string(&Locale::en)),
+        format!("Completed: {}", delete_marker_deleted.to_formatted_string(&Locale::en)),
+        format!("Per second: {}", actions_per_second.to_formatted_string(&Locale::en)),
+    ];
+
+    for item in text_items.into_iter() {
+        let text_size = text_size(scale, font, &item);
+        draw_text_mut(
+            &mut overlay_image,
+            Rgb([0, 0, 0]),
+            25,
+            start_y,
+            scale,
+            font,
+            &item,
+        );
+
+        start_y += text_size.1 + line_buffer
+    }
+
+    let state_frame = render_frame(&job.files, image_size, opt.output_size);
+    overlay(&mut overlay_image, &state_frame, 0, 400);
+    overlay_image
+}
+
+// Runs on its own thread: pulls rendering jobs off `jobs`, composites and hands each
+// to `sink`, and checkpoints if due - overlapping frame N's resize/text/encode with
+// the main thread mutating the state machine for frame N+1.
+fn run_frame_worker(
+    jobs: std::sync::mpsc::Receiver<FrameJob>,
+    offsets: Arc<Vec<usize>>,
+    image_size: usize,
+    font: Font,
+    opt: Opt,
+    window_anchor: Option<DateTime<Utc>>,
+) {
+    let mut sink = FrameSink::new(&opt, opt.output_size, opt.output_size + 400);
+
+    for job in jobs {
+        let overlay_image = compose_overlay_image(&font, &opt, image_size, &job);
+        sink.write(job.idx, &opt.state_dir, &overlay_image);
 
-    let opt: Opt = Opt::from_args();
+        if opt.resume && job.idx % CHECKPOINT_INTERVAL == 0 {
+            log::info!(
+                "Writing resume checkpoint at bucket {} (frame {})",
+                job.key,
+                job.idx
+            );
+            let state = State {
+                offsets: (*offsets).clone(),
+                files: (*job.files).clone(),
+                image_size,
+                output_image_size: opt.output_size,
+                counts: job.counts,
+            };
+            Checkpoint {
+                state,
+                bucket: job.key,
+                frame_idx: job.idx,
+                window_anchor,
+            }
+            .save(&opt.state_dir);
+        }
+    }
+
+    sink.finish();
+}
+
+// 940370485
+// 940360641
 
+// Reads every gzipped segment file in `dir`, and returns the per-segment start
+// offsets into the flattened file list alongside the total file count.
+fn read_offsets(dir: PathBuf) -> (Vec<usize>, usize) {
     let mut segments: Vec<Segment> = vec![];
 
-    let segments_file = fs::read_dir(opt.segments).expect("Error reading segment dir");
+    let segments_file = fs::read_dir(dir).expect("Error reading segment dir");
     for segment in segments_file {
         let segment = segment.expect("Error reading file");
         let file = File::open(segment.path()).expect("Error reading file");
@@ -226,10 +632,14 @@ fn main() {
         })
         .collect();
 
-    let mut state = State::new(offsets, total_files, opt.output_size);
+    (offsets, total_files)
+}
 
-    let mut event_iterators = vec![];
-    let events_files = fs::read_dir(opt.events).expect("Error reading event dir");
+// Opens every gzipped event file in `dir` as a lazy, per-file iterator ready for
+// `kmerge_by`.
+fn read_event_iterators(dir: PathBuf) -> Vec<Box<dyn Iterator<Item = Event>>> {
+    let mut event_iterators: Vec<Box<dyn Iterator<Item = Event>>> = vec![];
+    let events_files = fs::read_dir(dir).expect("Error reading event dir");
     for event in events_files {
         let event = event.expect("Error reading file");
         log::info!("Reading event file {:?}", event.path());
@@ -240,31 +650,152 @@ fn main() {
             .map(|v| v.expect("Error reading line"));
         event_iterators.push(Box::new(event_lines));
     }
+    event_iterators
+}
 
-    let font = Vec::from(include_bytes!("DejaVuSans.ttf") as &[u8]);
-    let font = Font::try_from_vec(font).unwrap();
+// Folds `bucket` down to the start of the fixed-size `window` it falls in, anchored at
+// `anchor` (so windows line up with `--from`/a resumed checkpoint rather than with
+// whatever the epoch happens to be).
+fn window_floor(bucket: DateTime<Utc>, anchor: DateTime<Utc>, window: chrono::Duration) -> DateTime<Utc> {
+    let window_idx = (bucket - anchor).num_milliseconds().div_euclid(window.num_milliseconds());
+    anchor + window * (window_idx as i32)
+}
+
+fn render(opt: Opt) {
+    // `FrameSink::new` truncates output.gif/output.mp4 on every invocation, and a
+    // resumed run only ever sees `FrameJob`s for buckets after the checkpoint - so
+    // `--resume` together with gif/mp4 would silently produce an animation missing
+    // every frame rendered before the crash. There's no "continue an existing encode"
+    // path for those formats, so refuse the combination outright.
+    if opt.resume && opt.output_format != OutputFormat::Frames {
+        panic!(
+            "--resume is not supported with --output-format {:?}: only `frames` mode can skip \
+             already-rendered output, gif/mp4 would silently drop every frame before the checkpoint",
+            opt.output_format
+        );
+    }
+
+    let (offsets, total_files) = read_offsets(opt.segments.clone());
+
+    let checkpoint = if opt.resume {
+        Checkpoint::load(&opt.state_dir)
+    } else {
+        None
+    };
+
+    let (mut state, resume_point, resume_window_anchor) = match checkpoint {
+        Some(checkpoint) => {
+            log::info!(
+                "Resuming from checkpoint at bucket {} (frame {})",
+                checkpoint.bucket,
+                checkpoint.frame_idx
+            );
+            let resume_point = (checkpoint.bucket, checkpoint.frame_idx);
+            (checkpoint.state, Some(resume_point), checkpoint.window_anchor)
+        }
+        None => (State::new(offsets, total_files, opt.output_size), None, None),
+    };
+
+    let event_iterators = read_event_iterators(opt.events.clone());
 
     // let mut frames = vec![];
-    let items = event_iterators
+    let window = opt.interval.as_ref().map(|d| chrono::Duration::from_std(**d).unwrap());
+    let resume_bucket = resume_point.map(|(bucket, _)| bucket);
+    let from = opt.from;
+    let to = opt.to;
+    let mut merged = event_iterators
         .into_iter()
         .kmerge_by(|a, b| a.bucket < b.bucket)
-        .group_by(|e| e.bucket);
+        // Applied once, after merging, so the skip is consistent across every source
+        // iterator instead of having to be threaded through each one individually.
+        // In `--interval` mode a checkpoint's bucket is the start of the window it
+        // rendered, and that whole window is already applied to the restored state.
+        .filter(move |e| match (resume_bucket, window) {
+            (None, _) => true,
+            (Some(checkpoint), Some(window)) => e.bucket >= checkpoint + window,
+            (Some(checkpoint), None) => e.bucket > checkpoint,
+        })
+        .filter(move |e| from.map_or(true, |from| e.bucket >= from))
+        .filter(move |e| to.map_or(true, |to| e.bucket <= to))
+        .peekable();
 
-    let mut previous_date_time: Option<DateTime<Utc>> = None;
+    // A fixed window anchored at the resumed checkpoint's anchor (so a resumed run's
+    // windows line up with the pre-crash portion of the same render), falling back to
+    // `--from`, or the first surviving event if neither is set; every event's bucket is
+    // folded down to the start of the window it falls in.
+    let window_anchor =
+        window.and(resume_window_anchor.or(opt.from).or_else(|| merged.peek().map(|e| e.bucket)));
+
+    let font = Vec::from(include_bytes!("DejaVuSans.ttf") as &[u8]);
+    let font = Font::try_from_vec(font).unwrap();
+
+    // `image_size` and `offsets` never change once the state machine starts, so they're
+    // captured once for the worker rather than resent with every frame job.
+    let image_size = state.image_size;
+    let offsets_for_worker = Arc::new(state.offsets.clone());
+    let (job_sender, job_receiver) = sync_channel::<FrameJob>(2);
+    let worker_opt = opt.clone();
+    let worker = std::thread::spawn(move || {
+        run_frame_worker(
+            job_receiver,
+            offsets_for_worker,
+            image_size,
+            font,
+            worker_opt,
+            window_anchor,
+        )
+    });
+
+    let items = merged.group_by(move |e| match (window, window_anchor) {
+        (Some(window), Some(anchor)) => window_floor(e.bucket, anchor, window),
+        _ => e.bucket,
+    });
+
+    let mut previous_date_time: Option<DateTime<Utc>> = resume_point.map(|(bucket, _)| bucket);
     let mut first_date_time: Option<DateTime<Utc>> = None;
+    let mut idx = resume_point.map_or(0, |(_, frame_idx)| frame_idx + 1);
+    // Tracks the next window we expect a group for, so gaps with no events can be
+    // filled with a repeated frame instead of silently compressing the timeline. A
+    // resumed run expects the window right after the checkpoint's, not the anchor.
+    let mut next_expected_key = match (window, resume_point) {
+        (Some(window), Some((bucket, _))) => Some(bucket + window),
+        _ => window_anchor,
+    };
+
+    for (key, group) in items.into_iter() {
+        if let (Some(window), Some(expected)) = (window, next_expected_key) {
+            let mut filler_key = expected;
+            // `state` isn't mutated across filler windows (no events land inside the gap),
+            // so the `files` snapshot is identical for every filler frame - clone it once
+            // instead of once per empty window.
+            let snapshot = Arc::new(state.files.clone());
+            while filler_key < key {
+                let first = *first_date_time.get_or_insert(filler_key);
+                job_sender
+                    .send(FrameJob {
+                        idx,
+                        key: filler_key,
+                        duration_since_start: filler_key - first,
+                        total_actions: 0,
+                        elapsed_secs: window.num_seconds(),
+                        counts: state.counts(),
+                        files: Arc::clone(&snapshot),
+                    })
+                    .expect("Frame worker thread terminated early");
+                idx += 1;
+                previous_date_time = Some(filler_key);
+                filler_key = filler_key + window;
+            }
+        }
 
-    for (idx, (key, group)) in items.into_iter().enumerate() {
         let previous_group = match previous_date_time {
             None => key,
             Some(v) => v,
         };
 
-        first_date_time = match first_date_time {
-            None => Some(key),
-            Some(v) => Some(v)
-        };
+        let first_date_time = *first_date_time.get_or_insert(key);
         // I don't know how to make this nicer :(
-        let duration_since_start = key - first_date_time.unwrap();
+        let duration_since_start = key - first_date_time;
 
         // log::info!("Processing group {} = {}", key, group.count());
         log::info!("Processing group {}", key);
@@ -278,76 +809,217 @@ fn main() {
             }
         }
 
-        let actions_per_second = total_actions.checked_div(key.timestamp() - previous_group.timestamp()).unwrap_or(0);
-
-        let present = state
-            .files
-            .iter()
-            .filter(|s| **s == FileState::Present)
-            .count();
-        let delete_marker = state
-            .files
-            .iter()
-            .filter(|s| **s == FileState::DeleteMarker)
-            .count();
-        let expired = state
-            .files
-            .iter()
-            .filter(|s| **s == FileState::Expired)
-            .count();
-        let delete_marker_deleted = state
-            .files
-            .iter()
-            .filter(|s| **s == FileState::DeleteMarkerDeleted)
-            .count();
-        let weird_case = state
-            .files
-            .iter()
-            .filter(|s| **s == FileState::WeirdCase)
-            .count();
-        log::info!("Present = {}, delete_marker = {}, expired = {}, delete_marked_deleted = {} weird_case = {}", present, delete_marker, expired, delete_marker_deleted, weird_case);
-        log::info!("Per second: {}", actions_per_second);
-
-        let mut overlay_image =
-            RgbImage::from_pixel(opt.output_size, opt.output_size + 400, Rgb([255, 255, 255]));
-
-        let scale = Scale {
-            x: 45.0,
-            y: 45.0,
-        };
+        let elapsed_secs = window
+            .map(|w| w.num_seconds())
+            .unwrap_or_else(|| key.timestamp() - previous_group.timestamp());
+        job_sender
+            .send(FrameJob {
+                idx,
+                key,
+                duration_since_start,
+                total_actions,
+                elapsed_secs,
+                counts: state.counts(),
+                files: Arc::new(state.files.clone()),
+            })
+            .expect("Frame worker thread terminated early");
 
-        let line_buffer = 20;
-        let mut start_y = 25;
-
-        let text_items = vec![
-            format!("Hours: {}", duration_since_start.num_hours()),
-            format!("Present: {}", present.to_formatted_string(&Locale::en)),
-            format!("Delete Marker: {}", delete_marker.to_formatted_string(&Locale::en)),
-            format!("Expired: {}", expired.to_formatted_string(&Locale::en)),
-            format!("Completed: {}", delete_marker_deleted.to_formatted_string(&Locale::en)),
-            format!("Per second: {}", actions_per_second.to_formatted_string(&Locale::en)),
-        ];
-
-        for item in text_items.into_iter() {
-            let text_size = text_size(scale, &font, &item);
-            draw_text_mut(
-                &mut overlay_image,
-                Rgb([0, 0, 0]),
-                25,
-                start_y,
-                scale,
-                &font,
-                &item,
-            );
+        idx += 1;
+        previous_date_time = Some(key);
+        next_expected_key = window.map(|w| key + w);
+    }
+
+    drop(job_sender);
+    worker.join().expect("Frame worker thread panicked");
+}
+
+// Runs the same ingestion and state machine as `render`, but writes a CSV timeseries
+// report instead of compositing and saving images - for auditing the weird rows and
+// charting the deletion curve without paying the rendering cost.
+fn verify(opt: VerifyOpt) {
+    let (offsets, total_files) = read_offsets(opt.segments);
+    // The image dimensions are irrelevant here since no frame is ever rendered.
+    let mut state = State::new(offsets, total_files, 0);
+    let event_iterators = read_event_iterators(opt.events);
+
+    let from = opt.from;
+    let to = opt.to;
+    let items = event_iterators
+        .into_iter()
+        .kmerge_by(|a, b| a.bucket < b.bucket)
+        .filter(move |e| from.map_or(true, |from| e.bucket >= from))
+        .filter(move |e| to.map_or(true, |to| e.bucket <= to))
+        .group_by(|e| e.bucket);
 
-            start_y += text_size.1 + line_buffer
+    let mut report = BufWriter::new(File::create(&opt.report).expect("Error creating report file"));
+    writeln!(
+        report,
+        "bucket,present,delete_marker,expired,delete_marker_deleted,weird_case,total_actions,actions_per_second"
+    )
+    .expect("Error writing report header");
+
+    // A sibling CSV alongside `--report`, so the "few hundred" weird transitions are an
+    // artifact a user can actually open in a spreadsheet, not just a log line that's gone
+    // the moment the run ends.
+    let weird_report_path = weird_case_report_path(&opt.report);
+    let mut weird_report =
+        BufWriter::new(File::create(&weird_report_path).expect("Error creating weird-case report file"));
+    writeln!(weird_report, "bucket,segment,number,operation,previous_state")
+        .expect("Error writing weird-case report header");
+
+    let mut previous_date_time: Option<DateTime<Utc>> = None;
+
+    for (key, group) in items.into_iter() {
+        let previous_group = previous_date_time.unwrap_or(key);
+        let mut total_actions = 0i64;
+
+        for event in group {
+            total_actions += event.items.len() as i64;
+
+            for item in event.items {
+                if let Some(previous_state) =
+                    state.set_item(event.segment, item as usize, &event.operation)
+                {
+                    log::warn!(
+                        "Weird case: segment={} number={} operation={:?} previous_state={:?}",
+                        event.segment,
+                        item,
+                        event.operation,
+                        previous_state
+                    );
+                    writeln!(
+                        weird_report,
+                        "{},{},{},{:?},{:?}",
+                        key.to_rfc3339(),
+                        event.segment,
+                        item,
+                        event.operation,
+                        previous_state
+                    )
+                    .expect("Error writing weird-case report row");
+                }
+            }
         }
 
-        let state_frame = state.get_frame();
-        overlay(&mut overlay_image, &state_frame, 0, 400);
-        let save_path = opt.state_dir.join(format!("{:0width$}.png", idx, width = 4));
-        overlay_image.save(save_path).expect("Error saving image");
+        let actions_per_second = total_actions
+            .checked_div(key.timestamp() - previous_group.timestamp())
+            .unwrap_or(0);
+        let [present, delete_marker, expired, delete_marker_deleted, weird_case] = state.counts();
+
+        writeln!(
+            report,
+            "{},{},{},{},{},{},{},{}",
+            key.to_rfc3339(),
+            present,
+            delete_marker,
+            expired,
+            delete_marker_deleted,
+            weird_case,
+            total_actions,
+            actions_per_second
+        )
+        .expect("Error writing report row");
 
         previous_date_time = Some(key);
     }
+
+    report.flush().expect("Error flushing report");
+    weird_report.flush().expect("Error flushing weird-case report");
+}
+
+// Derives the sibling path for the weird-case CSV from the main `--report` path, e.g.
+// `report.csv` -> `report.weird.csv`.
+fn weird_case_report_path(report: &Path) -> PathBuf {
+    let stem = report
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "report".to_string());
+    match report.extension() {
+        Some(ext) => report.with_file_name(format!("{}.weird.{}", stem, ext.to_string_lossy())),
+        None => report.with_file_name(format!("{}.weird", stem)),
+    }
+}
+
+fn main() {
+    SimpleLogger::new().init().unwrap();
+
+    match Cli::from_args() {
+        Cli::Render(opt) => render(opt),
+        Cli::Verify(opt) => verify(opt),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_with_files(count: usize) -> State {
+        State::new(vec![0], count, 0)
+    }
+
+    #[test]
+    fn set_item_present_to_delete_marker_updates_counts() {
+        let mut state = state_with_files(3);
+        assert_eq!(state.counts(), [3, 0, 0, 0, 0]);
+
+        let result = state.set_item(1, 1, &Operation::Delete);
+        assert!(result.is_none());
+        assert_eq!(state.counts(), [2, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn set_item_full_lifecycle_to_delete_marker_deleted() {
+        let mut state = state_with_files(1);
+        state.set_item(1, 1, &Operation::Delete);
+        state.set_item(1, 1, &Operation::Expire);
+        assert_eq!(state.counts(), [0, 0, 1, 0, 0]);
+
+        state.set_item(1, 1, &Operation::Expire);
+        assert_eq!(state.counts(), [0, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn set_item_reports_first_weird_transition_only() {
+        let mut state = state_with_files(1);
+        state.set_item(1, 1, &Operation::Delete); // -> DeleteMarker
+        state.set_item(1, 1, &Operation::Delete); // -> DeleteMarkerDeleted (exception arm)
+        assert_eq!(state.counts(), [0, 0, 0, 1, 0]);
+
+        let first_weird = state.set_item(1, 1, &Operation::Delete);
+        assert_eq!(first_weird, Some(FileState::DeleteMarkerDeleted));
+        assert_eq!(state.counts(), [0, 0, 0, 0, 1]);
+
+        // Replaying another event against an already-weird file is a no-op and must not
+        // be reported a second time.
+        let second_weird = state.set_item(1, 1, &Operation::Expire);
+        assert_eq!(second_weird, None);
+        assert_eq!(state.counts(), [0, 0, 0, 0, 1]);
+    }
+
+    fn sample_anchor() -> DateTime<Utc> {
+        parse_datetime("2022-09-02 15:55:00.0").unwrap()
+    }
+
+    #[test]
+    fn window_floor_folds_down_to_the_anchored_window_start() {
+        let anchor = sample_anchor();
+        let window = chrono::Duration::minutes(15);
+
+        assert_eq!(window_floor(anchor, anchor, window), anchor);
+        assert_eq!(window_floor(anchor + chrono::Duration::minutes(7), anchor, window), anchor);
+
+        let next_window = anchor + chrono::Duration::minutes(16);
+        assert_eq!(window_floor(next_window, anchor, window), anchor + window);
+    }
+
+    #[test]
+    fn window_floor_rounds_toward_negative_infinity_before_the_anchor() {
+        let anchor = sample_anchor();
+        let window = chrono::Duration::minutes(15);
+
+        // div_euclid must floor rather than truncate toward zero, so a timestamp just
+        // before the anchor falls in the window immediately preceding it, not the anchor's.
+        let before = anchor - chrono::Duration::minutes(1);
+        assert_eq!(window_floor(before, anchor, window), anchor - window);
+    }
 }